@@ -7,6 +7,9 @@ use std::sync::Arc;
 #[cfg(feature="containers")]
 use dkregistry::v2::Client as RegistryClient;
 
+#[cfg(feature="containers")]
+use dkregistry::v2::manifest::Manifest;
+
 #[cfg(feature="containers")]
 use futures::stream::StreamExt;
 
@@ -25,6 +28,15 @@ use quire::{
     ast::{Ast, ScalarKind, Tag},
 };
 
+#[cfg(feature="containers")]
+use sha2::{Sha256, Digest as _};
+
+#[cfg(feature="containers")]
+use flate2::{write::GzEncoder, Compression as GzCompression};
+
+#[cfg(feature="containers")]
+use filetime::FileTime;
+
 #[cfg(feature="containers")]
 use crate::{
     builder::commands::tarcmd::TarCmd,
@@ -41,15 +53,164 @@ const DEFAULT_IMAGE_TAG: &str = "latest";
 const DOCKER_LAYERS_CACHE_PATH: &str = "/vagga/cache/docker-layers";
 const DOCKER_LAYERS_DOWNLOAD_CONCURRENCY: usize = 2;
 
+const DEFAULT_PLATFORM_OS: &str = "linux";
+const DEFAULT_PLATFORM_ARCHITECTURE: &str = "amd64";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The compression a layer tarball is stored under. `TarCmd` only knows how
+/// to unpack gzip, so `Zstd`/`None` layers are transparently re-wrapped as
+/// gzip before being handed to it (see `ensure_gzip_tar`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LayerCompression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl LayerCompression {
+    /// Maps an OCI/Docker layer media type (e.g.
+    /// `application/vnd.oci.image.layer.v1.tar+zstd`) to its compression.
+    fn from_media_type(media_type: &str) -> LayerCompression {
+        if media_type.ends_with("+zstd") {
+            LayerCompression::Zstd
+        } else if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+            LayerCompression::Gzip
+        } else {
+            LayerCompression::None
+        }
+    }
+
+    /// Sniffs the compression of a tarball from its leading magic bytes,
+    /// for layers (e.g. from a local archive) with no declared media type.
+    fn sniff(path: &Path) -> Result<LayerCompression, String> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| format!("Cannot open layer file to detect compression: {}", e))?;
+        let mut magic = [0u8; 4];
+        let read = file.read(&mut magic)
+            .map_err(|e| format!("Cannot read layer file to detect compression: {}", e))?;
+        let magic = &magic[..read];
+        if magic.starts_with(&GZIP_MAGIC) {
+            Ok(LayerCompression::Gzip)
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Ok(LayerCompression::Zstd)
+        } else {
+            Ok(LayerCompression::None)
+        }
+    }
+
+    /// The filename suffix used for a blob cached under this compression.
+    fn extension(&self) -> &'static str {
+        match self {
+            LayerCompression::Gzip => "tar.gz",
+            LayerCompression::Zstd => "tar.zst",
+            LayerCompression::None => "tar",
+        }
+    }
+}
+
+#[cfg(all(test, feature="containers"))]
+mod layer_compression_tests {
+    use super::LayerCompression;
+    use std::io::Write;
+
+    #[test]
+    fn media_type_detects_zstd() {
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.oci.image.layer.v1.tar+zstd"),
+            LayerCompression::Zstd
+        );
+    }
+
+    #[test]
+    fn media_type_detects_gzip() {
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.oci.image.layer.v1.tar+gzip"),
+            LayerCompression::Gzip
+        );
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.docker.image.rootfs.diff.tar.gzip"),
+            LayerCompression::Gzip
+        );
+    }
+
+    #[test]
+    fn media_type_defaults_to_none() {
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.oci.image.layer.v1.tar"),
+            LayerCompression::None
+        );
+    }
+
+    fn write_temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("vagga-docker-sniff-test-{}-{}", std::process::id(), name));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn sniff_detects_gzip_magic() {
+        let path = write_temp_file("gzip", &[0x1f, 0x8b, 0x08, 0x00]);
+        assert_eq!(LayerCompression::sniff(&path).unwrap(), LayerCompression::Gzip);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sniff_detects_zstd_magic() {
+        let path = write_temp_file("zstd", &[0x28, 0xb5, 0x2f, 0xfd]);
+        assert_eq!(LayerCompression::sniff(&path).unwrap(), LayerCompression::Zstd);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sniff_defaults_to_none_for_plain_tar() {
+        let path = write_temp_file("plain", b"plain tar contents");
+        assert_eq!(LayerCompression::sniff(&path).unwrap(), LayerCompression::None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DockerImage {
     pub registry: String,
     pub image: String,
     pub tag: String,
     pub insecure: Option<bool>,
+    /// `os/architecture` to pull when `image:tag` resolves to a manifest
+    /// list (multi-arch image), e.g. `linux/arm64`. Defaults to
+    /// `linux/amd64` when not set.
+    pub platform: Option<String>,
     pub path: PathBuf,
 }
 
+/// Credentials used to authenticate with a private registry.
+///
+/// Configured per-registry-host in `docker_registries` in the settings file,
+/// e.g.:
+/// ```yaml
+/// docker-registries:
+///   registry-1.docker.io:
+///     username: myuser
+///     password: mypassword
+/// ```
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DockerRegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for DockerRegistryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DockerRegistryCredentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 impl DockerImage {
     pub fn config() -> V::Structure<'static> {
         V::Structure::new()
@@ -57,6 +218,7 @@ impl DockerImage {
         .member("image", V::Scalar::new())
         .member("tag", V::Scalar::new().default(DEFAULT_IMAGE_TAG))
         .member("insecure", V::Scalar::new().optional())
+        .member("platform", V::Scalar::new().optional())
         .member("path", V::Directory::new().absolute(true).default("/"))
         .parser(parse_image)
     }
@@ -119,6 +281,7 @@ impl BuildStep for DockerImage {
         hash.field("image", &self.image);
         hash.field("tag", &self.tag);
         hash.opt_field("insecure", &self.insecure);
+        hash.opt_field("platform", &self.platform);
         hash.field("path", &self.path);
         Ok(())
     }
@@ -131,6 +294,9 @@ impl BuildStep for DockerImage {
         if !insecure {
             capsule::ensure(&mut guard.ctx.capsule, &[capsule::Https])?;
         }
+        let credentials = registry_credentials(
+            &self.registry, &guard.ctx.settings.docker_registries
+        );
         Dir::new(DOCKER_LAYERS_CACHE_PATH)
             .recursive(true)
             .create()
@@ -138,12 +304,15 @@ impl BuildStep for DockerImage {
                 format!("Cannot create docker layers cache directory: {}", e)
             )?;
         let dst_path = Path::new("/vagga/root").join(&self.path.strip_prefix("/").unwrap());
+        let verify_cache = guard.ctx.settings.docker_verify_cached_layers;
+        let cache_size_limit = guard.ctx.settings.docker_layers_cache_size_limit;
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .map_err(|e| format!("Error creating tokio runtime: {}", e))?
             .block_on(download_and_unpack_image(
-                &self.registry, insecure, &self.image, &self.tag, &dst_path
+                &self.registry, insecure, credentials, &self.image, &self.tag,
+                self.platform.as_deref(), &dst_path, verify_cache, cache_size_limit,
             ))?;
         Ok(())
     }
@@ -163,6 +332,272 @@ fn is_insecure_registry(
     insecure_registries.contains(registry_host)
 }
 
+/// Looks up configured credentials for `registry` in the `docker_registries`
+/// settings map, keyed by registry host (port included, if any).
+fn registry_credentials(
+    registry: &str,
+    registries: &BTreeMap<String, DockerRegistryCredentials>,
+) -> Option<DockerRegistryCredentials> {
+    registries.get(registry).cloned()
+}
+
+/// Path of the exclusive lock file guarding a cached blob. Held by
+/// `download_blob` while writing it, by `unpack_layer` while reading it, and
+/// by `evict_lru_blobs` while removing it, so none of those three ever run
+/// concurrently against the same blob.
+#[cfg(feature="containers")]
+fn blob_lock_path(blob_path: &Path) -> PathBuf {
+    let file_name = blob_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    blob_path.with_file_name(format!(".{}.lock", file_name))
+}
+
+// NOTE: this source tree has no step-dispatch enum in scope to register
+// `LocalDockerImage` on (the module that lists build steps reachable from
+// vagga.yaml isn't part of this checkout) — wire it in next to `DockerImage`
+// wherever that enum lives, the same way `DockerImage` is registered there.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocalDockerImage {
+    pub archive: PathBuf,
+    pub path: PathBuf,
+}
+
+impl LocalDockerImage {
+    pub fn config() -> V::Structure<'static> {
+        V::Structure::new()
+        .member("archive", V::Scalar::new())
+        .member("path", V::Directory::new().absolute(true).default("/"))
+    }
+}
+
+impl BuildStep for LocalDockerImage {
+    fn name(&self) -> &'static str {
+        "LocalDockerImage"
+    }
+
+    #[cfg(feature="containers")]
+    fn hash(&self, cfg: &Config, hash: &mut Digest) -> Result<(), VersionError> {
+        hash.field("archive", &self.archive);
+        hash.field("path", &self.path);
+        // The archive isn't itself part of the vagga.yaml, so its metadata
+        // has to be hashed explicitly: otherwise overwriting `archive` in
+        // place with new image data wouldn't invalidate the build cache.
+        let archive_path = cfg.config_dir.join(&self.archive);
+        let metadata = std::fs::metadata(&archive_path)
+            .map_err(|e|
+                format!("Cannot stat docker archive {}: {}", archive_path.display(), e)
+            )?;
+        hash.field("archive_size", &metadata.len());
+        if let Ok(modified) = metadata.modified() {
+            if let Ok(since_epoch) = modified.duration_since(std::time::UNIX_EPOCH) {
+                hash.field("archive_mtime", &since_epoch.as_secs());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="containers")]
+    fn build(&self, guard: &mut Guard, _build: bool) -> Result<(), StepError> {
+        let archive_path = guard.ctx.config_dir.join(&self.archive);
+        Dir::new(DOCKER_LAYERS_CACHE_PATH)
+            .recursive(true)
+            .create()
+            .map_err(|e|
+                format!("Cannot create docker layers cache directory: {}", e)
+            )?;
+        let dst_path = Path::new("/vagga/root").join(&self.path.strip_prefix("/").unwrap());
+        println!("Importing docker image from: {}", archive_path.display());
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Error creating tokio runtime: {}", e))?
+            .block_on(import_local_image(&archive_path, &dst_path))?;
+        Ok(())
+    }
+
+    fn is_dependent_on(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// An entry of the `manifest.json` found at the root of a `docker save`
+/// archive. Only the fields needed to unpack the image are parsed.
+#[cfg(feature="containers")]
+#[derive(Deserialize, Debug)]
+struct SaveManifestEntry {
+    #[serde(rename = "RepoTags", default)]
+    repo_tags: Vec<String>,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+/// Imports a container root filesystem from a `docker save`-style archive
+/// (a tarball containing `manifest.json`, an image config, and one tarball
+/// per layer) by unpacking its layers, in order, into `dst_path`, reusing
+/// the same `unpack_layer` machinery the registry puller uses.
+#[cfg(feature="containers")]
+async fn import_local_image(archive_path: &Path, dst_path: &Path) -> Result<(), String> {
+    let manifest = read_save_manifest(archive_path)?;
+    let entry = select_save_manifest_entry(manifest, archive_path)?;
+
+    for layer_name in entry.layers {
+        let layer_path = extract_archive_entry(archive_path, &layer_name)?;
+        let compression = LayerCompression::sniff(&layer_path)?;
+        unpack_layer(layer_name, layer_path, compression, dst_path.to_path_buf(), true).await?;
+    }
+    Ok(())
+}
+
+/// `docker save` can bundle several images/tags in one archive. Vagga has
+/// nowhere to ask which one was meant, so require the archive to contain
+/// exactly one and error out listing what was found otherwise, rather than
+/// silently importing just the first and dropping the rest.
+#[cfg(feature="containers")]
+fn select_save_manifest_entry(
+    mut manifest: Vec<SaveManifestEntry>, archive_path: &Path,
+) -> Result<SaveManifestEntry, String> {
+    if manifest.len() > 1 {
+        let images = manifest.iter()
+            .map(|entry| {
+                if entry.repo_tags.is_empty() {
+                    "<untagged>".to_string()
+                } else {
+                    entry.repo_tags.join(", ")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(format!(
+            "Docker archive {} contains {} images ({}), expected exactly one; \
+             re-export it with a single image/tag",
+            archive_path.display(), manifest.len(), images
+        ));
+    }
+    manifest.pop()
+        .ok_or_else(||
+            format!("No entries found in manifest.json of {}", archive_path.display())
+        )
+}
+
+#[cfg(all(test, feature="containers"))]
+mod select_save_manifest_entry_tests {
+    use super::{select_save_manifest_entry, SaveManifestEntry};
+    use std::path::Path;
+
+    fn entry(repo_tags: &[&str], layers: &[&str]) -> SaveManifestEntry {
+        SaveManifestEntry {
+            repo_tags: repo_tags.iter().map(|s| s.to_string()).collect(),
+            layers: layers.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn returns_the_single_entry() {
+        let manifest = vec!(entry(&["app:latest"], &["layer1.tar"]));
+        let result = select_save_manifest_entry(manifest, Path::new("archive.tar")).unwrap();
+        assert_eq!(result.layers, vec!("layer1.tar".to_string()));
+    }
+
+    #[test]
+    fn errors_listing_tags_when_multiple_images_are_present() {
+        let manifest = vec!(
+            entry(&["app:latest"], &["layer1.tar"]),
+            entry(&["app:old"], &["layer2.tar"]),
+        );
+        let err = select_save_manifest_entry(manifest, Path::new("archive.tar")).unwrap_err();
+        assert!(err.contains("app:latest"));
+        assert!(err.contains("app:old"));
+        assert!(err.contains("2 images"));
+    }
+
+    #[test]
+    fn errors_listing_untagged_when_an_image_has_no_repo_tags() {
+        let manifest = vec!(
+            entry(&["app:latest"], &["layer1.tar"]),
+            entry(&[], &["layer2.tar"]),
+        );
+        let err = select_save_manifest_entry(manifest, Path::new("archive.tar")).unwrap_err();
+        assert!(err.contains("<untagged>"));
+    }
+
+    #[test]
+    fn errors_on_an_empty_manifest() {
+        let err = select_save_manifest_entry(vec!(), Path::new("archive.tar")).unwrap_err();
+        assert!(err.contains("No entries found"));
+    }
+}
+
+/// Reads and parses `manifest.json` out of a `docker save` archive.
+#[cfg(feature="containers")]
+fn read_save_manifest(archive_path: &Path) -> Result<Vec<SaveManifestEntry>, String> {
+    let file = std::fs::File::open(archive_path)
+        .map_err(|e| format!("Cannot open docker archive {}: {}", archive_path.display(), e))?;
+    let mut archive = tar::Archive::new(file);
+    let entries = archive.entries()
+        .map_err(|e| format!("Cannot read docker archive {}: {}", archive_path.display(), e))?;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| format!("Error reading docker archive entry: {}", e))?;
+        let path = entry.path()
+            .map_err(|e| format!("Invalid docker archive entry path: {}", e))?;
+        if path.as_ref() == Path::new("manifest.json") {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)
+                .map_err(|e| format!("Cannot read manifest.json: {}", e))?;
+            return serde_json::from_str(&contents)
+                .map_err(|e| format!("Cannot parse manifest.json: {}", e));
+        }
+    }
+    Err(format!("No manifest.json found in docker archive {}", archive_path.display()))
+}
+
+/// Extracts a single named entry (a layer tarball) out of a `docker save`
+/// archive into a temporary file under the docker layers cache, returning
+/// its path.
+#[cfg(feature="containers")]
+fn extract_archive_entry(archive_path: &Path, entry_name: &str) -> Result<PathBuf, String> {
+    let file = std::fs::File::open(archive_path)
+        .map_err(|e| format!("Cannot open docker archive {}: {}", archive_path.display(), e))?;
+    let mut archive = tar::Archive::new(file);
+    let entries = archive.entries()
+        .map_err(|e| format!("Cannot read docker archive {}: {}", archive_path.display(), e))?;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| format!("Error reading docker archive entry: {}", e))?;
+        let path = entry.path()
+            .map_err(|e| format!("Invalid docker archive entry path: {}", e))?;
+        if path.as_ref() == Path::new(entry_name) {
+            let base_path = Path::new(DOCKER_LAYERS_CACHE_PATH)
+                .join(format!("local-layer-{:x}", tiny_hash(entry_name)));
+            let tmp_path = unique_temp_path(&base_path, "extract");
+            let extract_result = (|| -> Result<(), String> {
+                let mut tmp_file = std::fs::File::create(&tmp_path)
+                    .map_err(|e| format!("Cannot create temporary layer file: {}", e))?;
+                std::io::copy(&mut entry, &mut tmp_file)
+                    .map_err(|e| format!("Cannot extract docker layer {}: {}", entry_name, e))?;
+                Ok(())
+            })();
+            if let Err(e) = extract_result {
+                let _ = remove_file(&tmp_path);
+                return Err(e);
+            }
+            return Ok(tmp_path);
+        }
+    }
+    Err(format!(
+        "Layer {:?} not found in docker archive {}", entry_name, archive_path.display()
+    ))
+}
+
+/// Short, stable, filesystem-safe hash of a layer's in-archive path, used
+/// to name its temporary extraction file.
+#[cfg(feature="containers")]
+fn tiny_hash(value: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(value.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
 /// See:
 /// - https://github.com/moby/moby/blob/v20.10.11/pkg/archive/whiteouts.go
 /// - https://github.com/moby/moby/blob/v20.10.11/pkg/archive/diff.go#L131
@@ -204,15 +639,18 @@ fn whiteout_entry_handler(entry: &Entry<Box<dyn Read>>, dst_path: &Path) -> Resu
 
 #[cfg(feature="containers")]
 async fn download_and_unpack_image(
-    registry: &str, insecure: bool, image: &str, tag: &str, dst_path: &Path
+    registry: &str, insecure: bool, credentials: Option<DockerRegistryCredentials>,
+    image: &str, tag: &str, platform: Option<&str>, dst_path: &Path, verify_cache: bool,
+    cache_size_limit: Option<u64>,
 ) -> Result<(), StepError> {
     let auth_scope = format!("repository:{}:pull", image);
-    let client = build_client(registry, insecure, &[&auth_scope]).await?;
+    let client = build_client(registry, insecure, credentials, &[&auth_scope]).await?;
 
     println!("Downloading docker image: {}/{}:{}", registry, image, tag);
-    let manifest = client.get_manifest(&image, &tag).await?;
+    let manifest = resolve_manifest(&client, image, tag, platform).await?;
 
     let layers_digests = manifest.layers_digests(None)?;
+    let layers_media_types = manifest.layers_media_types(None)?;
 
     let layers_download_semaphore = Arc::new(
         Semaphore::new(DOCKER_LAYERS_DOWNLOAD_CONCURRENCY)
@@ -222,9 +660,10 @@ async fn download_and_unpack_image(
 
     let mut layers_futures = vec!();
     let mut unpack_channels = vec!();
-    for digest in &layers_digests {
+    for (digest, media_type) in layers_digests.iter().zip(layers_media_types.iter()) {
         let image = image.to_string();
         let digest = digest.clone();
+        let compression = LayerCompression::from_media_type(media_type);
         let client = client.clone();
         let sem = layers_download_semaphore.clone();
         let (tx, rx) = oneshot::channel();
@@ -232,9 +671,9 @@ async fn download_and_unpack_image(
         let download_future = tokio::spawn(async move {
             if let Ok(_guard) = sem.acquire().await {
                 println!("Downloading docker layer: {}", &digest);
-                match download_blob(&client, &image, &digest).await {
+                match download_blob(&client, &image, &digest, compression, verify_cache).await {
                     Ok(layer_path) => {
-                        if let Err(_) = tx.send((digest.clone(), layer_path)) {
+                        if let Err(_) = tx.send((digest.clone(), layer_path, compression)) {
                             return Err(format!("Error sending downloaded layer"));
                         }
                         Ok(())
@@ -252,9 +691,9 @@ async fn download_and_unpack_image(
     let unpack_future = tokio::spawn(async move {
         for ch in unpack_channels {
             match ch.await {
-                Ok((digest, layer_path)) => {
+                Ok((digest, layer_path, compression)) => {
                     let dst_path = dst_path.clone();
-                    if let Err(e) = unpack_layer(digest, layer_path, dst_path).await {
+                    if let Err(e) = unpack_layer(digest, layer_path, compression, dst_path, false).await {
                         return Err(e);
                     }
                 }
@@ -280,36 +719,203 @@ async fn download_and_unpack_image(
         .map_err(|e| format!("Error waiting unpack future: {}", e))??;
 
     if !layers_errors.is_empty() {
-        Err(layers_errors.into())
-    } else {
-        Ok(())
+        return Err(layers_errors.into());
     }
+
+    if let Some(size_limit) = cache_size_limit {
+        tokio::task::spawn_blocking(move || evict_lru_blobs(size_limit))
+            .await
+            .map_err(|e| format!("Error waiting cache eviction future: {}", e))??;
+    }
+
+    Ok(())
 }
 
+/// Unpacks a single layer tarball into `dst_path`. `layer_path` is left in
+/// place afterwards unless `cleanup_source` is set — the registry puller
+/// points it at the persistent content-addressed cache blob and wants it
+/// kept, while `import_local_image` points it at a one-off scratch file
+/// extracted from a `docker save` archive and wants it removed.
 async fn unpack_layer(
-    digest: String, layer_path: PathBuf, dst_path: PathBuf
+    digest: String, layer_path: PathBuf, compression: LayerCompression, dst_path: PathBuf,
+    cleanup_source: bool,
 ) -> Result<(), String> {
     let unpack_future_res = tokio::task::spawn_blocking(move || {
+        // Hold the blob's lock for the whole read, so `evict_lru_blobs`
+        // can't remove it out from under us mid-unpack.
+        let lockfile = blob_lock_path(&layer_path);
+        let lock_msg = format!("Another process downloads blob: {}", digest);
+        let _lock = Lock::exclusive_wait(lockfile, true, &lock_msg)
+            .map_err(|e| format!("Error taking exclusive lock: {}", e))?;
+        let gzip_path = ensure_gzip_tar(&layer_path, compression)?;
         println!("Unpacking docker layer: {}", digest);
-        TarCmd::new(&layer_path, &dst_path)
+        let unpack_result = TarCmd::new(&gzip_path, &dst_path)
             .preserve_owner(true)
             .entry_handler(whiteout_entry_handler)
-            .unpack()
+            .unpack();
+        if gzip_path != layer_path {
+            remove_file(&gzip_path)
+                .map_err(|e| format!("Cannot remove temporary re-wrapped layer file: {}", e))?;
+        }
+        if cleanup_source {
+            remove_file(&layer_path)
+                .map_err(|e| format!("Cannot remove temporary extracted layer file: {}", e))?;
+        }
+        unpack_result
     }).await;
     unpack_future_res
         .map_err(|e| format!("Error waiting a unpack layer future: {}", e))?
         .map_err(|e| format!("Error unpacking docker layer: {}", e))
 }
 
+/// `TarCmd` only unpacks gzip-compressed tarballs. When `path` isn't
+/// already gzip, streams it through a decompressor (for `Zstd`) or as-is
+/// (for `None`) into a freshly gzip-compressed temporary file alongside it,
+/// and returns that path; otherwise returns `path` unchanged.
+#[cfg(feature="containers")]
+fn ensure_gzip_tar(path: &Path, compression: LayerCompression) -> Result<PathBuf, String> {
+    if compression == LayerCompression::Gzip {
+        return Ok(path.to_path_buf());
+    }
+
+    let gzip_path = unique_temp_path(path, "regzip");
+    let rewrap_result = (|| -> Result<(), String> {
+        let dst_file = std::fs::File::create(&gzip_path)
+            .map_err(|e| format!("Cannot create temporary layer file: {}", e))?;
+        let mut encoder = GzEncoder::new(dst_file, GzCompression::fast());
+
+        let src_file = std::fs::File::open(path)
+            .map_err(|e| format!("Cannot open layer file: {}", e))?;
+        match compression {
+            LayerCompression::Gzip => unreachable!(),
+            LayerCompression::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::new(src_file)
+                    .map_err(|e| format!("Cannot open zstd layer stream: {}", e))?;
+                std::io::copy(&mut decoder, &mut encoder)
+                    .map_err(|e| format!("Cannot re-wrap zstd layer as gzip: {}", e))?;
+            }
+            LayerCompression::None => {
+                let mut src_file = src_file;
+                std::io::copy(&mut src_file, &mut encoder)
+                    .map_err(|e| format!("Cannot re-wrap plain layer as gzip: {}", e))?;
+            }
+        }
+        encoder.finish()
+            .map_err(|e| format!("Cannot finalize re-wrapped layer: {}", e))?;
+        Ok(())
+    })();
+
+    if let Err(e) = rewrap_result {
+        let _ = remove_file(&gzip_path);
+        return Err(e);
+    }
+    Ok(gzip_path)
+}
+
+/// A temporary file path alongside `path`, named uniquely per process and
+/// call so concurrent vagga builds touching the same digest never collide
+/// on (or race to clean up) each other's temp file.
+#[cfg(feature="containers")]
+fn unique_temp_path(path: &Path, tag: &str) -> PathBuf {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("layer");
+    path.with_file_name(format!(".{}.{}.{}.{}.tmp", file_name, tag, std::process::id(), n))
+}
+
+/// Fetches the manifest for `image:tag`, transparently resolving a manifest
+/// list / OCI image index to the manifest for `platform` (or
+/// `linux/amd64` by default).
+#[cfg(feature="containers")]
+async fn resolve_manifest(
+    client: &RegistryClient, image: &str, tag: &str, platform: Option<&str>,
+) -> Result<Manifest, StepError> {
+    let (os, architecture) = match platform {
+        Some(platform) => {
+            platform.split_once('/')
+                .ok_or(format!(
+                    "Invalid platform {:?}, expected \"os/architecture\"", platform
+                ))?
+        }
+        None => (DEFAULT_PLATFORM_OS, DEFAULT_PLATFORM_ARCHITECTURE),
+    };
+
+    let manifest = client.get_manifest(image, tag).await?;
+    match manifest {
+        Manifest::ML(manifest_list) => {
+            let platforms: Vec<(String, String, String)> = manifest_list.manifests.iter()
+                .map(|m| (m.platform.os.clone(), m.platform.architecture.clone(), m.digest.clone()))
+                .collect();
+            let digest = select_platform_digest(&platforms, os, architecture)
+                .map_err(|e| format!("{} in image index {}:{}", e, image, tag))?;
+            client.get_manifest(image, digest).await
+                .map_err(|e| format!("Error fetching platform-specific manifest: {}", e).into())
+        }
+        manifest => Ok(manifest),
+    }
+}
+
+/// Picks the digest of the manifest matching `os`/`architecture` out of a
+/// manifest list's `(os, architecture, digest)` platform entries.
+#[cfg(feature="containers")]
+fn select_platform_digest<'a>(
+    platforms: &'a [(String, String, String)], os: &str, architecture: &str,
+) -> Result<&'a str, String> {
+    platforms.iter()
+        .find(|(entry_os, entry_arch, _)| entry_os == os && entry_arch == architecture)
+        .map(|(_, _, digest)| digest.as_str())
+        .ok_or_else(|| {
+            let available = platforms.iter()
+                .map(|(entry_os, entry_arch, _)| format!("{}/{}", entry_os, entry_arch))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("No manifest for platform {}/{}; available: {}", os, architecture, available)
+        })
+}
+
+#[cfg(all(test, feature="containers"))]
+mod platform_tests {
+    use super::select_platform_digest;
+
+    fn platforms() -> Vec<(String, String, String)> {
+        vec![
+            ("linux".to_string(), "amd64".to_string(), "sha256:aaa".to_string()),
+            ("linux".to_string(), "arm64".to_string(), "sha256:bbb".to_string()),
+            ("windows".to_string(), "amd64".to_string(), "sha256:ccc".to_string()),
+        ]
+    }
+
+    #[test]
+    fn finds_matching_platform() {
+        let platforms = platforms();
+        assert_eq!(select_platform_digest(&platforms, "linux", "amd64"), Ok("sha256:aaa"));
+        assert_eq!(select_platform_digest(&platforms, "linux", "arm64"), Ok("sha256:bbb"));
+    }
+
+    #[test]
+    fn errors_with_available_platforms_when_no_match() {
+        let platforms = platforms();
+        let err = select_platform_digest(&platforms, "linux", "arm").unwrap_err();
+        assert!(err.contains("linux/amd64"));
+        assert!(err.contains("linux/arm64"));
+        assert!(err.contains("windows/amd64"));
+    }
+}
+
 #[cfg(feature="containers")]
 async fn build_client(
-    registry: &str, insecure: bool, auth_scopes: &[&str]
+    registry: &str, insecure: bool, credentials: Option<DockerRegistryCredentials>,
+    auth_scopes: &[&str]
 ) -> Result<Arc<RegistryClient>, StepError> {
+    let (username, password) = match credentials {
+        Some(creds) => (Some(creds.username), Some(creds.password)),
+        None => (None, None),
+    };
     let client_config = RegistryClient::configure()
         .registry(registry)
         .insecure_registry(insecure)
-        .username(None)
-        .password(None);
+        .username(username)
+        .password(password);
     let client = client_config.build()?;
 
     let client = match client.is_auth().await {
@@ -322,7 +928,8 @@ async fn build_client(
 
 #[cfg(feature="containers")]
 async fn download_blob(
-    client: &RegistryClient, image: &str, layer_digest: &str
+    client: &RegistryClient, image: &str, layer_digest: &str,
+    compression: LayerCompression, verify_cached: bool,
 ) -> Result<PathBuf, String> {
     let digest = layer_digest.split_once(':')
         .ok_or(format!("Invalid layer digest: {}", layer_digest))?
@@ -330,15 +937,50 @@ async fn download_blob(
     let short_digest = &digest[..12];
 
     let layers_cache = Path::new(DOCKER_LAYERS_CACHE_PATH);
-    let blob_file_name = format!("{}.tar.gz", digest);
+    let blob_file_name = format!("{}.{}", digest, compression.extension());
     let blob_path = layers_cache.join(&blob_file_name);
     match tokio::fs::symlink_metadata(&blob_path).await {
+        Ok(_) if verify_cached => {
+            // Hold the blob's lock across both the read and the purge-if-
+            // corrupt, so this can't race `evict_lru_blobs` unlinking the
+            // blob out from under `File::open` between the existence check
+            // and the hash read.
+            let lockfile = blob_lock_path(&blob_path);
+            let lock_msg = format!("Another process uses blob: {}", &short_digest);
+            let verify_path = blob_path.clone();
+            let expected_digest = digest.to_string();
+            let verify_result = tokio::task::spawn_blocking(move || {
+                let _lock = Lock::exclusive_wait(lockfile, true, &lock_msg)
+                    .map_err(|e| format!("Error taking exclusive lock: {}", e))?;
+                let mut file = std::fs::File::open(&verify_path)
+                    .map_err(|e| format!("Cannot open blob file for verification: {}", e))?;
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut file, &mut hasher)
+                    .map_err(|e| format!("Cannot read blob file for verification: {}", e))?;
+                let actual_digest = format!("{:x}", hasher.finalize());
+                if actual_digest != expected_digest {
+                    match remove_file(&verify_path) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::NotFound => {}
+                        Err(e) => return Err(format!("Cannot remove corrupted cached blob: {}", e)),
+                    }
+                }
+                Ok(actual_digest)
+            }).await
+                .map_err(|e| format!("Error waiting a lock file future: {}", e))??;
+            if verify_result != digest {
+                return Err(format!(
+                    "Cached docker blob {} was corrupted (expected sha256:{}, got sha256:{}) \
+                     and has been purged from the cache; retry the build to re-download it",
+                    &short_digest, digest, verify_result
+                ));
+            }
+        }
         Ok(_) => {}
         Err(e) if e.kind() == ErrorKind::NotFound => {
-            let lock_file_name = format!(".{}.lock", &blob_file_name);
+            let lockfile = blob_lock_path(&blob_path);
             let lock_msg = format!("Another process downloads blob: {}", &short_digest);
             let lock_fut = tokio::task::spawn_blocking(move || {
-                let lockfile = layers_cache.join(lock_file_name);
                 Lock::exclusive_wait(lockfile, true, &lock_msg)
             });
             let _lock = lock_fut.await
@@ -356,11 +998,23 @@ async fn download_blob(
                         .map_err(|e| format!("Error getting docker blob response: {}", e))?;
                     let mut blob_file = tokio::fs::File::create(&blob_tmp_path).await
                         .map_err(|e| format!("Cannot create layer file: {}", e))?;
+                    let mut hasher = Sha256::new();
                     while let Some(chunk) = blob_stream.next().await {
                         let chunk = chunk.map_err(|e| format!("Error fetching layer chunk: {}", e))?;
+                        hasher.update(&chunk);
                         blob_file.write_all(&chunk).await
                             .map_err(|e| format!("Cannot write blob file: {}", e))?;
                     }
+                    drop(blob_file);
+                    let actual_digest = format!("{:x}", hasher.finalize());
+                    if actual_digest != digest {
+                        tokio::fs::remove_file(&blob_tmp_path).await
+                            .map_err(|e| format!("Cannot remove corrupted blob file: {}", e))?;
+                        return Err(format!(
+                            "Downloaded docker blob {} is corrupted (expected sha256:{}, got sha256:{})",
+                            &short_digest, digest, actual_digest
+                        ));
+                    }
                     tokio::fs::rename(&blob_tmp_path, &blob_path).await
                         .map_err(|e| format!("Cannot rename docker blob: {}", e))?;
                 }
@@ -370,5 +1024,134 @@ async fn download_blob(
         }
         Err(e) => return Err(format!("{}", e)),
     }
+    touch_blob(&blob_path).await?;
     Ok(blob_path)
+}
+
+/// Bumps a cached blob's mtime to now, so LRU eviction (`evict_lru_blobs`)
+/// treats it as recently used.
+#[cfg(feature="containers")]
+async fn touch_blob(blob_path: &Path) -> Result<(), String> {
+    let blob_path = blob_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        filetime::set_file_mtime(&blob_path, FileTime::now())
+            .map_err(|e| format!("Cannot update access time of cached blob: {}", e))
+    }).await.map_err(|e| format!("Error waiting touch blob future: {}", e))?
+}
+
+/// Evicts least-recently-used cached layer blobs (by mtime) until the
+/// docker layers cache is at or under `size_limit` bytes. Because blobs are
+/// content-addressed by digest, eviction is always safe: a missing blob is
+/// simply re-downloaded on the next pull.
+#[cfg(feature="containers")]
+fn evict_lru_blobs(size_limit: u64) -> Result<(), String> {
+    let layers_cache = Path::new(DOCKER_LAYERS_CACHE_PATH);
+
+    let mut blobs = vec!();
+    let entries = std::fs::read_dir(layers_cache)
+        .map_err(|e| format!("Cannot read docker layers cache directory: {}", e))?;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| format!("Cannot read docker layers cache entry: {}", e))?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        // Skip lock files and in-progress downloads; only cached blobs
+        // (`<digest>.tar.gz` / `.tar.zst` / `.tar`) are eviction candidates.
+        if file_name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()
+            .map_err(|e| format!("Cannot stat docker layers cache entry: {}", e))?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        blobs.push((entry.path(), metadata.len(), mtime));
+    }
+
+    for (blob_path, _) in select_eviction_candidates(blobs, size_limit) {
+        let file_name = blob_path.file_name().and_then(|n| n.to_str())
+            .unwrap_or("");
+        let lock_msg = format!("Another process uses blob: {}", file_name);
+        let lockfile = blob_lock_path(&blob_path);
+        let _lock = match Lock::exclusive_wait(lockfile, true, &lock_msg) {
+            Ok(lock) => lock,
+            // `exclusive_wait` waits out another process holding the lock;
+            // it only fails if the lock can't be taken at all, in which
+            // case leave this blob alone and keep evicting older ones.
+            Err(_) => continue,
+        };
+        match remove_file(&blob_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Cannot evict cached docker blob: {}", e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Pure selection logic behind `evict_lru_blobs`: picks the least-recently
+/// used blobs (oldest mtime first) to remove until the remaining total would
+/// be at or under `size_limit`. Split out from `evict_lru_blobs` so the
+/// ordering/threshold behavior can be unit tested without touching the
+/// filesystem.
+#[cfg(feature="containers")]
+fn select_eviction_candidates(
+    mut blobs: Vec<(PathBuf, u64, FileTime)>, size_limit: u64,
+) -> Vec<(PathBuf, u64)> {
+    let mut total_size: u64 = blobs.iter().map(|(_, size, _)| size).sum();
+    if total_size <= size_limit {
+        return Vec::new();
+    }
+
+    blobs.sort_by_key(|(_, _, mtime)| *mtime);
+
+    let mut evicted = vec!();
+    for (blob_path, size, _) in blobs {
+        if total_size <= size_limit {
+            break;
+        }
+        total_size -= size;
+        evicted.push((blob_path, size));
+    }
+    evicted
+}
+
+#[cfg(all(test, feature="containers"))]
+mod eviction_tests {
+    use super::select_eviction_candidates;
+    use std::path::PathBuf;
+    use filetime::FileTime;
+
+    fn blob(name: &str, size: u64, seconds_since_epoch: i64) -> (PathBuf, u64, FileTime) {
+        (PathBuf::from(name), size, FileTime::from_unix_time(seconds_since_epoch, 0))
+    }
+
+    #[test]
+    fn keeps_everything_under_the_limit() {
+        let blobs = vec!(blob("a", 10, 1), blob("b", 10, 2));
+        assert_eq!(select_eviction_candidates(blobs, 100), Vec::new());
+    }
+
+    #[test]
+    fn evicts_oldest_first_until_under_the_limit() {
+        let blobs = vec!(
+            blob("newest", 10, 30),
+            blob("oldest", 10, 10),
+            blob("middle", 10, 20),
+        );
+        let evicted = select_eviction_candidates(blobs, 15);
+        assert_eq!(evicted, vec!(
+            (PathBuf::from("oldest"), 10),
+            (PathBuf::from("middle"), 10),
+        ));
+    }
+
+    #[test]
+    fn stops_as_soon_as_the_limit_is_satisfied() {
+        let blobs = vec!(blob("oldest", 10, 10), blob("newest", 10, 20));
+        let evicted = select_eviction_candidates(blobs, 10);
+        assert_eq!(evicted, vec!((PathBuf::from("oldest"), 10)));
+    }
 }
\ No newline at end of file